@@ -1,12 +1,13 @@
-#![feature(external_doc)]
-#![doc(include = "../README.md")]
+#![doc = include_str!("../README.md")]
 
 use err_derive::Error;
 
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::hash::Hash;
-use std::sync::{Arc, RwLock};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread;
 use std::time::{Duration, SystemTime};
 
 /// Convenient type definition returned by all [`KeyValueStore`] methods.
@@ -23,17 +24,340 @@ pub enum KeyValueStoreError {
     /// Returned when a write lock is acquired but is poisoned.
     #[error(display = "Acquired write lock was poisoned")]
     PoisonedWriteLock,
+    /// Returned to a [`KeyValueStore::get_or_insert_with`] follower when
+    /// the leader's `init` panicked, or its insert back into the store
+    /// failed, before a value was ever produced.
+    #[error(display = "the leading get_or_insert_with caller did not produce a value")]
+    LeaderFailed,
 }
 
+/// Why an entry left a [`KeyValueStore`], passed to listeners registered
+/// with [`KeyValueStore::with_eviction_listener`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// The entry was removed by an explicit call to [`KeyValueStore::remove`].
+    Explicit,
+    /// The entry was found to be past its expiration.
+    Expired,
+    /// The entry was overwritten by a new value for the same key via
+    /// [`KeyValueStore::insert`].
+    Replaced,
+    /// The entry was evicted to make room under
+    /// [`KeyValueStore::with_capacity`].
+    Capacity,
+}
+
+/// Number of independent hash functions used by [`FrequencySketch`]. Four
+/// is the figure most count-min sketch implementations (including Caffeine's
+/// TinyLFU) converge on as the sweet spot between estimate accuracy and the
+/// cost of computing more slots per access.
+const SKETCH_HASHES: usize = 4;
+
+/// Distinct odd multipliers used to derive [`SKETCH_HASHES`] independent
+/// slots from a single 64-bit key hash, instead of hashing the key
+/// separately per function.
+const SKETCH_SEEDS: [u64; SKETCH_HASHES] = [
+    0x9E37_79B9_7F4A_7C15,
+    0xC2B2_AE3D_27D4_EB4F,
+    0x1656_67B1_9E37_79F9,
+    0x27D4_EB2F_1656_67C5,
+];
+
+/// Maximum value a single counter can hold before it saturates. Kept small
+/// (a nibble) so the whole sketch stays cache-friendly even for large
+/// capacities.
+const SKETCH_COUNTER_MAX: u8 = 15;
+
+/// A small count-min sketch used to approximate how often a key has been
+/// seen recently, used to gate admission of new keys under
+/// [`KeyValueStore::with_capacity`] (a TinyLFU-style policy). Counters are
+/// periodically halved so the estimate tracks recent activity rather than
+/// an all-time total.
+#[derive(Debug)]
+struct FrequencySketch {
+    counters: Vec<u8>,
+    additions: usize,
+}
+
+/// How many counters to allocate per distinct key the sketch is sized
+/// for. Caffeine uses a similar multiple of its expected entry count so
+/// that, even with [`SKETCH_HASHES`] probes per key, two live keys only
+/// collide on a given slot by chance rather than by construction.
+const SKETCH_WIDTH_PER_KEY: usize = 8;
+
+/// Floor on the counter array's length, so a sketch for a tiny capacity
+/// (even `with_capacity(1)`) still has enough slots that `SKETCH_HASHES`
+/// probes per key don't guarantee self-collision.
+const SKETCH_MIN_WIDTH: usize = 64;
+
+impl FrequencySketch {
+    /// Create a sketch sized for roughly `capacity` distinct keys. The
+    /// counter array is sized independently of `capacity` (a multiple of
+    /// it, with a floor), not just `capacity` itself — sizing it 1:1 with
+    /// `capacity` guarantees self-collision once `capacity` is smaller
+    /// than a handful of [`SKETCH_HASHES`]-sized probes, which made
+    /// `estimate()` return arbitrary counts rather than real recency.
+    fn new(capacity: usize) -> FrequencySketch {
+        let width = capacity
+            .saturating_mul(SKETCH_WIDTH_PER_KEY)
+            .max(SKETCH_MIN_WIDTH);
+        FrequencySketch {
+            counters: vec![0; width],
+            additions: 0,
+        }
+    }
+
+    fn slots(&self, key_hash: u64) -> [usize; SKETCH_HASHES] {
+        let len = self.counters.len() as u64;
+        let mut slots = [0usize; SKETCH_HASHES];
+        for (slot, seed) in slots.iter_mut().zip(SKETCH_SEEDS.iter()) {
+            *slot = ((key_hash ^ seed).wrapping_mul(0x9E37_79B9_7F4A_7C15) % len) as usize;
+        }
+        slots
+    }
+
+    /// Estimate how often `key_hash` has been seen. We take the minimum
+    /// across all of its slots, since any slot it doesn't share with
+    /// another key is an exact count, and shared slots can only overcount.
+    fn estimate(&self, key_hash: u64) -> u8 {
+        self.slots(key_hash)
+            .iter()
+            .map(|&slot| self.counters[slot])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn increment(&mut self, key_hash: u64) {
+        let mut incremented = false;
+        for slot in self.slots(key_hash).iter() {
+            if self.counters[*slot] < SKETCH_COUNTER_MAX {
+                self.counters[*slot] += 1;
+                incremented = true;
+            }
+        }
+        if incremented {
+            self.additions += 1;
+            if self.additions >= self.counters.len() * 10 {
+                self.age();
+            }
+        }
+    }
+
+    /// Halve every counter, so recent history gradually outweighs old
+    /// history instead of a key's popularity from hours ago pinning it in
+    /// the cache forever.
+    fn age(&mut self) {
+        for counter in &mut self.counters {
+            *counter /= 2;
+        }
+        self.additions = 0;
+    }
+}
+
+fn hash_key<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A single map entry plus the intrusive doubly-linked-list handles used to
+/// track recency for LRU eviction. `prev`/`next` point at neighboring keys
+/// rather than raw pointers so the whole structure can live safely inside
+/// a plain `HashMap`.
+#[derive(Debug, Clone)]
+struct Entry<K, V> {
+    value: V,
+    expiration: Option<SystemTime>,
+    prev: Option<K>,
+    next: Option<K>,
+}
+
+/// The data guarded by a single shard's lock: its slice of the map, plus
+/// the head (least-recently-used) and tail (most-recently-used) of its
+/// own recency list. Kept together so capacity eviction always has the
+/// full picture under a single lock acquisition.
+///
+/// A [`KeyValueStore`] holds a `Vec` of these (see
+/// [`KeyValueStore::with_shards`]), each independently locked, so writes
+/// to keys in different shards never contend with each other.
+#[derive(Debug)]
+struct Inner<K, V> {
+    map: HashMap<K, Entry<K, V>>,
+    head: Option<K>,
+    tail: Option<K>,
+    /// Count of entries carrying an expiration, maintained incrementally
+    /// so the background janitor spawned by
+    /// [`KeyValueStore::with_eviction_interval`] can skip sweeping this
+    /// shard entirely when nothing in it can expire.
+    expiring: usize,
+}
+
+impl<K, V> Inner<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    fn new() -> Inner<K, V> {
+        Inner {
+            map: HashMap::new(),
+            head: None,
+            tail: None,
+            expiring: 0,
+        }
+    }
+
+    /// Remove `key` and its entry from both the map and the recency list,
+    /// keeping `expiring` in sync. Returns the removed entry, if any.
+    fn take(&mut self, key: &K) -> Option<Entry<K, V>> {
+        self.unlink(key);
+        let entry = self.map.remove(key);
+        if let Some(entry) = &entry {
+            if entry.expiration.is_some() {
+                self.expiring -= 1;
+            }
+        }
+        entry
+    }
+
+    /// Detach `key` from the recency list without removing it from `map`.
+    fn unlink(&mut self, key: &K) {
+        let (prev, next) = match self.map.get(key) {
+            Some(entry) => (entry.prev.clone(), entry.next.clone()),
+            None => return,
+        };
+        match &prev {
+            Some(prev_key) => self.map.get_mut(prev_key).unwrap().next = next.clone(),
+            None => self.head = next.clone(),
+        }
+        match &next {
+            Some(next_key) => self.map.get_mut(next_key).unwrap().prev = prev.clone(),
+            None => self.tail = prev.clone(),
+        }
+    }
+
+    /// Attach `key`, which must already be present in `map`, as the new
+    /// tail (most-recently-used) of the recency list.
+    fn push_tail(&mut self, key: K) {
+        let old_tail = self.tail.clone();
+        if let Some(entry) = self.map.get_mut(&key) {
+            entry.prev = old_tail.clone();
+            entry.next = None;
+        }
+        match &old_tail {
+            Some(tail_key) => self.map.get_mut(tail_key).unwrap().next = Some(key.clone()),
+            None => self.head = Some(key.clone()),
+        }
+        self.tail = Some(key);
+    }
+
+    /// Move `key` to the tail of the recency list, marking it as the most
+    /// recently used entry.
+    fn touch(&mut self, key: &K) {
+        if !self.map.contains_key(key) {
+            return;
+        }
+        self.unlink(key);
+        self.push_tail(key.clone());
+    }
+}
+
+/// The state of a single in-flight [`KeyValueStore::get_or_insert_with`]
+/// computation, shared by whichever caller is running `init` (the
+/// "leader") and any others racing on the same key (the "followers").
+enum SlotState<V> {
+    Pending,
+    Done(V),
+    /// The leader's `init` panicked, or its `insert` back into the store
+    /// failed, before ever reaching `Done`.
+    Failed,
+}
+
+/// One entry in the single-flight map: followers block on the
+/// [`Condvar`] until the leader moves the [`Mutex`] from
+/// [`SlotState::Pending`] to [`SlotState::Done`].
+type InflightSlot<V> = Arc<(Mutex<SlotState<V>>, Condvar)>;
+
+/// Cleans up a leader's single-flight slot no matter how
+/// [`KeyValueStore::get_or_insert_with`] exits — success, an early `?`
+/// return, or `init` panicking. Set `disarmed` once the leader has
+/// installed [`SlotState::Done`] itself; otherwise, on drop, the slot is
+/// marked [`SlotState::Failed`], every blocked follower is woken, and the
+/// slot is removed so the next caller for this key starts a fresh
+/// attempt instead of waiting on a `Condvar` nothing will ever notify.
+struct LeaderGuard<K: Eq + Hash, V> {
+    inflight: Arc<RwLock<HashMap<K, InflightSlot<V>>>>,
+    key: K,
+    slot: InflightSlot<V>,
+    disarmed: bool,
+}
+
+impl<K, V> Drop for LeaderGuard<K, V>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        if self.disarmed {
+            return;
+        }
+        let (lock, condvar) = &*self.slot;
+        if let Ok(mut state) = lock.lock() {
+            *state = SlotState::Failed;
+        }
+        condvar.notify_all();
+        if let Ok(mut inflight) = self.inflight.write() {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
+/// The callback type registered via
+/// [`KeyValueStore::with_eviction_listener`]. Stored behind its own lock,
+/// separate from every shard, so that setting it through one handle is
+/// immediately visible to every clone and to the background janitor
+/// thread, regardless of which order those were built in.
+type Listener<K, V> = Arc<RwLock<Option<Arc<dyn Fn(&K, V, RemovalCause) + Send + Sync>>>>;
+
+/// Entries displaced by an insert (an overwrite, or a capacity eviction),
+/// collected while a shard's lock is held so [`KeyValueStore::dispatch`]
+/// can report them to the eviction listener afterward.
+type RemovedEntries<K, V> = Vec<(K, V, RemovalCause)>;
+
+/// Result of [`KeyValueStore::apply_insert_locked`]: the previous value
+/// for the key, if any, plus anything it displaced.
+type InsertOutcome<K, V> = Result<(Option<V>, RemovedEntries<K, V>), KeyValueStoreError>;
+
 /// Primary structure in this library. It is a general-purpose,
 /// key-value store that is thread safe and allows one to set
 /// expiration times on entries. It's primary purpose is to
 /// wrap an `Arc<RwLock<HashMap>>` and expose a limited set of
 /// functions for inserting, removing, and retrieving values
 /// by key.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct KeyValueStore<K, V> {
-    inner: Arc<RwLock<HashMap<K, (V, Option<SystemTime>)>>>,
+    /// Independently-locked buckets the map is partitioned across; see
+    /// [`KeyValueStore::with_shards`]. A freshly constructed store has
+    /// exactly one, so by default this behaves like a single
+    /// `Arc<RwLock<HashMap>>`, as it always has.
+    shards: Arc<Vec<RwLock<Inner<K, V>>>>,
+    capacity: Option<usize>,
+    sketch: Option<Arc<RwLock<FrequencySketch>>>,
+    listener: Listener<K, V>,
+    /// Per-key single-flight coordination for
+    /// [`KeyValueStore::get_or_insert_with`], kept separate from `shards`
+    /// so a long-running `init` closure never holds a shard's lock.
+    inflight: Arc<RwLock<HashMap<K, InflightSlot<V>>>>,
+    /// See [`KeyValueStore::with_poison_recovery`].
+    recover_poison: bool,
+}
+
+// Manual impl because `Listener` holds a `dyn Fn`, which isn't `Debug`,
+// so this can't be derived without dragging that bound onto every caller.
+impl<K, V> Debug for KeyValueStore<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyValueStore")
+            .field("shard_count", &self.shards.len())
+            .field("capacity", &self.capacity)
+            .finish()
+    }
 }
 
 impl<K, V> KeyValueStore<K, V>
@@ -41,13 +365,267 @@ where
     K: Eq + Hash + Clone,
     V: Clone,
 {
-    /// Create a new, empty [`KeyValueStore`].
+    /// Create a new, empty [`KeyValueStore`] with no maximum size; entries
+    /// are only ever removed by expiration or explicit [`KeyValueStore::remove`].
     pub fn new() -> KeyValueStore<K, V> {
         KeyValueStore {
-            inner: Arc::new(RwLock::new(HashMap::new())),
+            shards: Arc::new(vec![RwLock::new(Inner::new())]),
+            capacity: None,
+            sketch: None,
+            listener: Arc::new(RwLock::new(None)),
+            inflight: Arc::new(RwLock::new(HashMap::new())),
+            recover_poison: false,
+        }
+    }
+
+    /// Create a new, empty [`KeyValueStore`] that holds at most
+    /// `max_entries` entries. Once full, inserting a new key evicts the
+    /// least-recently-used entry to make room, following the same
+    /// admission policy as moka's size-bounded caches: a small
+    /// TinyLFU-style frequency sketch is consulted so that a newly-seen
+    /// key only displaces the eviction victim when it's estimated to be
+    /// accessed more often, which protects a cache under scan-heavy
+    /// workloads from being churned out by one-off keys.
+    ///
+    /// `max_entries` must be greater than zero.
+    pub fn with_capacity(max_entries: usize) -> KeyValueStore<K, V> {
+        assert!(max_entries > 0, "capacity must be greater than zero");
+        KeyValueStore {
+            shards: Arc::new(vec![RwLock::new(Inner::new())]),
+            capacity: Some(max_entries),
+            sketch: Some(Arc::new(RwLock::new(FrequencySketch::new(max_entries)))),
+            listener: Arc::new(RwLock::new(None)),
+            inflight: Arc::new(RwLock::new(HashMap::new())),
+            recover_poison: false,
+        }
+    }
+
+    /// Partition the store's storage into `shard_count` independently
+    /// locked buckets (a power of two), following the bucket-distributed
+    /// locking approach of chashmap. Each key is routed to a shard by the
+    /// low bits of its hash, so `insert`/`remove` on keys in different
+    /// shards proceed fully in parallel instead of serializing on one
+    /// lock, and reads never contend with writes to unrelated keys.
+    ///
+    /// Any entries already in the store are redistributed across the new
+    /// shards. Not yet supported together with [`KeyValueStore::with_capacity`]:
+    /// the LRU list this crate uses for eviction is a single list per
+    /// shard, and there's no cheap way to keep a single global
+    /// least-recently-used ordering once writes are spread across
+    /// independently locked buckets.
+    ///
+    /// This builds a brand-new `shards` allocation rather than mutating
+    /// the existing one in place, so it can only be called while `self`
+    /// is the only handle to its storage: any clone taken beforehand
+    /// would otherwise keep pointing at the old, pre-reshard storage
+    /// with no error or warning, silently forking the "shared by every
+    /// clone" contract this type's doc comment promises. Panics if a
+    /// clone of this store still exists; call this before handing out
+    /// any clones (typically right after construction), not after.
+    pub fn with_shards(self, shard_count: usize) -> KeyValueStore<K, V> {
+        assert!(
+            shard_count > 0 && shard_count.is_power_of_two(),
+            "shard_count must be a power of two"
+        );
+        assert!(
+            self.capacity.is_none(),
+            "with_shards doesn't support combining with with_capacity yet"
+        );
+        assert_eq!(
+            Arc::strong_count(&self.shards),
+            1,
+            "with_shards can't reshard while another clone of this store exists; \
+             call it before sharing any clones"
+        );
+
+        let mask = (shard_count - 1) as u64;
+        let mut new_shards: Vec<RwLock<Inner<K, V>>> =
+            (0..shard_count).map(|_| RwLock::new(Inner::new())).collect();
+
+        for old_shard in self.shards.iter() {
+            let mut old = old_shard
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            for (key, entry) in old.map.drain() {
+                let shard_index = (hash_key(&key) & mask) as usize;
+                let new_shard = new_shards[shard_index].get_mut().unwrap();
+                if entry.expiration.is_some() {
+                    new_shard.expiring += 1;
+                }
+                new_shard.map.insert(
+                    key.clone(),
+                    Entry {
+                        value: entry.value,
+                        expiration: entry.expiration,
+                        prev: None,
+                        next: None,
+                    },
+                );
+                new_shard.push_tail(key);
+            }
+        }
+
+        KeyValueStore {
+            shards: Arc::new(new_shards),
+            capacity: self.capacity,
+            sketch: self.sketch,
+            listener: self.listener,
+            inflight: self.inflight,
+            recover_poison: self.recover_poison,
+        }
+    }
+
+    /// The maximum number of entries this store will hold, or `None` if
+    /// it is unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Make `get`, `insert`, and `remove` transparently recover from a
+    /// poisoned shard lock (one left poisoned by a thread that panicked
+    /// while holding it) instead of returning
+    /// [`KeyValueStoreError::PoisonedReadLock`] /
+    /// [`KeyValueStoreError::PoisonedWriteLock`] forever after.
+    ///
+    /// Recovery works the same way `std`'s own poisoning model intends:
+    /// the guard is reclaimed via `PoisonError::into_inner`, so if the
+    /// panicking thread was partway through a mutation, the recovered
+    /// state may reflect that partial mutation. This trades consistency
+    /// for availability and is never applied unless opted into here.
+    pub fn with_poison_recovery(mut self) -> KeyValueStore<K, V> {
+        self.recover_poison = true;
+        self
+    }
+
+    /// Reclaim every shard's lock after a panic while holding it left the
+    /// lock poisoned, restoring normal operation without needing
+    /// [`KeyValueStore::with_poison_recovery`]. Like that mode, the
+    /// recovered state may reflect a partially-applied mutation from the
+    /// thread that panicked; this method doesn't attempt to detect or
+    /// repair that, only to make the store usable again.
+    pub fn clear_poison(&mut self) {
+        for shard in self.shards.iter() {
+            shard.clear_poison();
+        }
+    }
+
+    /// Register a callback to be invoked, with the reason, whenever an
+    /// entry leaves the store — on [`KeyValueStore::remove`], on
+    /// expiration, on being overwritten by a new value for the same key,
+    /// or on capacity eviction. Borrowed from moka's eviction-listener
+    /// idea.
+    ///
+    /// The listener is always invoked after the relevant lock has been
+    /// released, so it's safe for it to call back into the same store
+    /// (e.g. to re-insert the removed value) without deadlocking.
+    ///
+    /// Because the listener is stored behind its own lock, shared across
+    /// every clone of the store, calling this before or after building
+    /// out a background janitor with
+    /// [`KeyValueStore::with_eviction_interval`] has the same effect.
+    pub fn with_eviction_listener(
+        self,
+        listener: impl Fn(&K, V, RemovalCause) + Send + Sync + 'static,
+    ) -> KeyValueStore<K, V> {
+        if let Ok(mut slot) = self.listener.write() {
+            *slot = Some(Arc::new(listener));
+        }
+        self
+    }
+
+    /// Fetch the registered listener, if any, and invoke it for each
+    /// removed `(key, value, cause)` tuple. Must only be called after the
+    /// write lock responsible for the removals has already been
+    /// released, so that a listener touching this same store can't
+    /// deadlock on re-entrancy.
+    fn dispatch(&self, removed: RemovedEntries<K, V>) -> Result<(), KeyValueStoreError> {
+        if removed.is_empty() {
+            return Ok(());
+        }
+        let listener = self
+            .listener
+            .read()
+            .map_err(|_| KeyValueStoreError::PoisonedReadLock)?
+            .clone();
+        if let Some(listener) = listener {
+            for (key, value, cause) in removed {
+                listener(&key, value, cause);
+            }
+        }
+        Ok(())
+    }
+
+    /// The index into `shards` responsible for a key with the given hash.
+    fn shard_index(&self, key_hash: u64) -> usize {
+        let mask = (self.shards.len() - 1) as u64;
+        (key_hash & mask) as usize
+    }
+
+    /// Acquire a shard's write lock, recovering from poison instead of
+    /// erroring if [`KeyValueStore::with_poison_recovery`] is in effect.
+    fn write_shard_at(
+        &self,
+        shard_index: usize,
+    ) -> Result<std::sync::RwLockWriteGuard<'_, Inner<K, V>>, KeyValueStoreError> {
+        match self.shards[shard_index].write() {
+            Ok(guard) => Ok(guard),
+            Err(poisoned) if self.recover_poison => Ok(poisoned.into_inner()),
+            Err(_) => Err(KeyValueStoreError::PoisonedWriteLock),
+        }
+    }
+
+    /// Acquire a shard's read lock, recovering from poison instead of
+    /// erroring if [`KeyValueStore::with_poison_recovery`] is in effect.
+    fn read_shard_at(
+        &self,
+        shard_index: usize,
+    ) -> Result<std::sync::RwLockReadGuard<'_, Inner<K, V>>, KeyValueStoreError> {
+        match self.shards[shard_index].read() {
+            Ok(guard) => Ok(guard),
+            Err(poisoned) if self.recover_poison => Ok(poisoned.into_inner()),
+            Err(_) => Err(KeyValueStoreError::PoisonedReadLock),
         }
     }
 
+    /// Acquire the write lock for the shard owning `key_hash`; see
+    /// [`KeyValueStore::write_shard_at`].
+    fn write_shard(
+        &self,
+        key_hash: u64,
+    ) -> Result<std::sync::RwLockWriteGuard<'_, Inner<K, V>>, KeyValueStoreError> {
+        self.write_shard_at(self.shard_index(key_hash))
+    }
+
+    /// Acquire the read lock for the shard owning `key_hash`; see
+    /// [`KeyValueStore::read_shard_at`].
+    fn read_shard(
+        &self,
+        key_hash: u64,
+    ) -> Result<std::sync::RwLockReadGuard<'_, Inner<K, V>>, KeyValueStoreError> {
+        self.read_shard_at(self.shard_index(key_hash))
+    }
+
+    /// The number of entries currently in the store, folded across every
+    /// shard. Like the other methods on this type, this has to acquire a
+    /// lock (one per shard, in turn), so it returns a
+    /// [`KeyValueStoreResult`]-shaped error if one of them is poisoned.
+    pub fn len(&self) -> Result<usize, KeyValueStoreError> {
+        let mut total = 0;
+        for shard in self.shards.iter() {
+            total += shard
+                .read()
+                .map_err(|_| KeyValueStoreError::PoisonedReadLock)?
+                .map
+                .len();
+        }
+        Ok(total)
+    }
+
+    /// Returns `true` if the store currently holds no entries.
+    pub fn is_empty(&self) -> Result<bool, KeyValueStoreError> {
+        Ok(self.len()? == 0)
+    }
+
     /// Insert a key with an associated value and an optional
     /// expiration. Expiration values are provided in the form
     /// of a [`std::time::Duration`], which will be used to
@@ -59,9 +637,22 @@ where
     /// time are updated with those given and the previous values
     /// are returned in the [`KeyValueStoreResult`].
     ///
+    /// If the store was created with [`KeyValueStore::with_capacity`] and
+    /// is full, inserting a new key evicts the least-recently-used entry
+    /// first. If the frequency sketch determines the new key is accessed
+    /// less often than the eviction victim, the insert is dropped
+    /// entirely (the victim is kept and `Ok(None)` is returned) rather
+    /// than thrashing the cache.
+    ///
     /// Calling this function will always cause it to attempt to
-    /// hold a write lock on the underlying `HashMap`, which means
-    /// that no other locks can be obtained.
+    /// hold a write lock on the shard owning `key`; other shards are
+    /// unaffected (see [`KeyValueStore::with_shards`]).
+    ///
+    /// If a [`KeyValueStore::with_eviction_listener`] callback is
+    /// registered, it fires once the write lock has been released: with
+    /// [`RemovalCause::Replaced`] if this call overwrote an existing key,
+    /// or with [`RemovalCause::Capacity`] if making room for this key
+    /// evicted the least-recently-used entry.
     pub fn insert(
         &mut self,
         key: K,
@@ -69,11 +660,97 @@ where
         expiration: Option<Duration>,
     ) -> KeyValueStoreResult<V> {
         let expiration = expiration.map(|duration| SystemTime::now() + duration);
-        let result = (*self.inner)
-            .write()
-            .map_err(|_| KeyValueStoreError::PoisonedWriteLock)?
-            .insert(key, (value, expiration));
-        Ok(result.map(|(value, _)| value))
+        let key_hash = hash_key(&key);
+        if let Some(sketch) = &self.sketch {
+            sketch
+                .write()
+                .map_err(|_| KeyValueStoreError::PoisonedWriteLock)?
+                .increment(key_hash);
+        }
+
+        let (result, removed) = {
+            let mut inner = self.write_shard(key_hash)?;
+            self.apply_insert_locked(&mut inner, key_hash, key, value, expiration)?
+        };
+
+        self.dispatch(removed)?;
+        Ok(result)
+    }
+
+    /// The guts of [`KeyValueStore::insert`] against an already-locked
+    /// shard: look for an existing entry to overwrite, otherwise run
+    /// capacity eviction and admission, then insert. Factored out so
+    /// [`Transaction::commit`] can apply staged inserts under locks it
+    /// already holds, without duplicating the admission/eviction logic.
+    ///
+    /// Returns the previous value, if any existed (or `None` if the key
+    /// is new, or if it was rejected by the admission policy), plus any
+    /// entries this insert displaced.
+    fn apply_insert_locked(
+        &self,
+        inner: &mut Inner<K, V>,
+        key_hash: u64,
+        key: K,
+        value: V,
+        expiration: Option<SystemTime>,
+    ) -> InsertOutcome<K, V> {
+        let mut removed = Vec::new();
+
+        let result = if inner.map.contains_key(&key) {
+            inner.unlink(&key);
+            let previous = {
+                let entry = inner.map.get_mut(&key).unwrap();
+                let previous = std::mem::replace(&mut entry.value, value);
+                match (entry.expiration.is_some(), expiration.is_some()) {
+                    (true, false) => inner.expiring -= 1,
+                    (false, true) => inner.expiring += 1,
+                    _ => {}
+                }
+                entry.expiration = expiration;
+                previous
+            };
+            inner.push_tail(key.clone());
+            removed.push((key, previous.clone(), RemovalCause::Replaced));
+            Some(previous)
+        } else {
+            if let Some(capacity) = self.capacity {
+                if inner.map.len() >= capacity {
+                    let victim = inner.head.clone();
+                    if let Some(victim_key) = victim {
+                        if let Some(sketch) = &self.sketch {
+                            let sketch = sketch
+                                .read()
+                                .map_err(|_| KeyValueStoreError::PoisonedReadLock)?;
+                            let victim_hash = hash_key(&victim_key);
+                            if sketch.estimate(key_hash) <= sketch.estimate(victim_hash) {
+                                // Not admitted; the victim is kept as-is.
+                                return Ok((None, removed));
+                            }
+                        }
+                        if let Some(victim_entry) = inner.take(&victim_key) {
+                            removed.push((victim_key, victim_entry.value, RemovalCause::Capacity));
+                        }
+                    }
+                }
+            }
+
+            if expiration.is_some() {
+                inner.expiring += 1;
+            }
+            inner.map.insert(
+                key.clone(),
+                Entry {
+                    value,
+                    expiration,
+                    prev: None,
+                    next: None,
+                },
+            );
+            inner.push_tail(key);
+            None
+        };
+
+        Ok((result, removed))
     }
 
     /// Get the value associated with the given key. Returns `Ok(None)`
@@ -81,26 +758,44 @@ where
     /// if the value was found, but has expired, at which point the entry
     /// will also be removed from the underlying map. For this reason
     /// the function takes `&mut self` rather than `&self`.
-    /// 
+    ///
     /// Calling this function will always cause it to attempt to
-    /// hold a read lock on the underlying `HashMap`, which means
-    /// that other read locks can be obtained, but a write lock cannot
-    /// be obtained. However, if the value retrieved has expired,
-    /// the read lock is released and the function will call
+    /// hold a read lock on the shard owning `key`, which means
+    /// that other read locks on that shard can be obtained, but a write
+    /// lock on it cannot be, while other shards are unaffected (see
+    /// [`KeyValueStore::with_shards`]). However, if the value retrieved
+    /// has expired, the read lock is released and the function will call
     /// [`KeyValueStore::remove`] which tries to obtain a write lock.
+    ///
+    /// If this store was created with [`KeyValueStore::with_capacity`],
+    /// a hit also moves the key to the most-recently-used position,
+    /// which requires briefly upgrading to a write lock instead.
     pub fn get(&mut self, key: &K) -> KeyValueStoreResult<V> {
         let now = SystemTime::now();
-        let result = (*self.inner)
-            .read()
-            .map_err(|_| KeyValueStoreError::PoisonedReadLock)?
-            .get(key)
-            .cloned();
+        let key_hash = hash_key(key);
+        let result = if self.capacity.is_some() {
+            let mut inner = self.write_shard(key_hash)?;
+            let found = inner
+                .map
+                .get(key)
+                .map(|entry| (entry.value.clone(), entry.expiration));
+            if found.is_some() {
+                inner.touch(key);
+            }
+            found
+        } else {
+            self.read_shard(key_hash)?
+                .map
+                .get(key)
+                .map(|entry| (entry.value.clone(), entry.expiration))
+        };
         if let Some((value, Some(expiration))) = result {
             if expiration < now {
                 // This doesn't create a dead write lock
-                // because the read lock has been already
+                // because the earlier lock has already
                 // been released.
-                self.remove(&key).map(|_| None)
+                self.remove_with_cause(&key, RemovalCause::Expired)
+                    .map(|_| None)
             } else {
                 Ok(Some(value))
             }
@@ -113,20 +808,332 @@ where
     /// the value, if the key was found, otherwise returns `Ok(None)`.
     ///
     /// Calling this function will always cause it to attempt to
-    /// hold a write lock on the underlying `HashMap`, which means 
-    /// no other locks can be obtained.
+    /// hold a write lock on the shard owning `key`; other shards are
+    /// unaffected (see [`KeyValueStore::with_shards`]).
+    ///
+    /// If a [`KeyValueStore::with_eviction_listener`] callback is
+    /// registered, it fires with [`RemovalCause::Explicit`] once the
+    /// write lock has been released.
     pub fn remove(&mut self, key: &K) -> KeyValueStoreResult<V> {
-        let result = (*self.inner)
-            .write()
-            .map_err(|_| KeyValueStoreError::PoisonedWriteLock)?
-            .remove(key);
-        Ok(result.map(|(value, _)| value))
+        self.remove_with_cause(key, RemovalCause::Explicit)
+    }
+
+    /// Shared implementation behind [`KeyValueStore::remove`] and the
+    /// lazy-expiration path in [`KeyValueStore::get`], which need to
+    /// report different [`RemovalCause`]s for what is otherwise the same
+    /// operation.
+    fn remove_with_cause(&mut self, key: &K, cause: RemovalCause) -> KeyValueStoreResult<V> {
+        let removed = {
+            let mut inner = self.write_shard(hash_key(key))?;
+            inner.take(key)
+        };
+        let value = removed.map(|entry| entry.value);
+        if let Some(value) = &value {
+            self.dispatch(vec![(key.clone(), value.clone(), cause)])?;
+        }
+        Ok(value)
+    }
+
+    /// Get the value for `key`, computing and inserting it with `init` if
+    /// it's missing or expired, analogous to moka's `get_with`.
+    ///
+    /// Concurrent callers racing on the same missing key only run `init`
+    /// once: whichever caller observes the key is missing first becomes
+    /// the "leader" and runs `init`, while the rest block on that key's
+    /// single-flight slot until the leader installs the value, and all
+    /// of them return the same result. This never blocks callers working
+    /// with unrelated keys.
+    ///
+    /// Always returns `Ok(Some(value))` on success; `Ok(None)` is not a
+    /// possible outcome of this method, but it shares
+    /// [`KeyValueStoreResult`] with the rest of this type for
+    /// consistency.
+    ///
+    /// If the leader's `init` panics, or its insert back into the store
+    /// fails, every follower wakes with
+    /// [`KeyValueStoreError::LeaderFailed`] instead of hanging forever,
+    /// and the slot is cleared so the next caller for that key becomes a
+    /// fresh leader.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: K,
+        ttl: Option<Duration>,
+        init: impl FnOnce() -> V,
+    ) -> KeyValueStoreResult<V> {
+        if let Some(value) = self.get(&key)? {
+            return Ok(Some(value));
+        }
+
+        let (slot, is_leader) = {
+            let mut inflight = self
+                .inflight
+                .write()
+                .map_err(|_| KeyValueStoreError::PoisonedWriteLock)?;
+            let is_leader = !inflight.contains_key(&key);
+            let slot = inflight
+                .entry(key.clone())
+                .or_insert_with(|| Arc::new((Mutex::new(SlotState::Pending), Condvar::new())))
+                .clone();
+            (slot, is_leader)
+        };
+
+        if is_leader {
+            // Cleans up this key's slot on every exit path — success,
+            // an `Err` return, or `init` panicking — so a leader that
+            // never produces a value can't strand followers (or every
+            // future caller for this key) waiting on a `Condvar` that's
+            // never notified.
+            let mut guard = LeaderGuard {
+                inflight: self.inflight.clone(),
+                key: key.clone(),
+                slot: slot.clone(),
+                disarmed: false,
+            };
+            let value = init();
+            self.insert(key, value.clone(), ttl)?;
+            guard.disarmed = true;
+            let (lock, condvar) = &*slot;
+            *lock
+                .lock()
+                .map_err(|_| KeyValueStoreError::PoisonedWriteLock)? = SlotState::Done(value.clone());
+            condvar.notify_all();
+            self.inflight
+                .write()
+                .map_err(|_| KeyValueStoreError::PoisonedWriteLock)?
+                .remove(&guard.key);
+            return Ok(Some(value));
+        }
+
+        let (lock, condvar) = &*slot;
+        let mut state = lock
+            .lock()
+            .map_err(|_| KeyValueStoreError::PoisonedWriteLock)?;
+        loop {
+            match &*state {
+                SlotState::Pending => {
+                    state = condvar
+                        .wait(state)
+                        .map_err(|_| KeyValueStoreError::PoisonedWriteLock)?;
+                }
+                SlotState::Done(value) => return Ok(Some(value.clone())),
+                SlotState::Failed => return Err(KeyValueStoreError::LeaderFailed),
+            }
+        }
+    }
+
+    /// Start a [`Transaction`] for staging a batch of `insert`/`remove`
+    /// operations that should apply all-or-nothing, inspired by fxfs's
+    /// `Transaction`. Nothing touches the store until
+    /// [`Transaction::commit`] is called; dropping the returned guard
+    /// without committing discards everything staged.
+    pub fn transaction(&mut self) -> Transaction<'_, K, V> {
+        Transaction {
+            store: self,
+            staged: Vec::new(),
+        }
+    }
+}
+
+impl<K, V> KeyValueStore<K, V>
+where
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Spawn a background thread that proactively sweeps expired entries
+    /// every `interval`, instead of relying solely on [`KeyValueStore::get`]
+    /// to lazily reclaim them. This mirrors moka's housekeeper design and
+    /// makes TTLs usable for long-lived caches that would otherwise hold
+    /// entries nobody ever reads again.
+    ///
+    /// The thread holds only a [`Weak`] reference to the store's shards,
+    /// so it never keeps the store alive by itself; it notices the last
+    /// strong handle has been dropped (via a failed [`Weak::upgrade`])
+    /// and exits. Each wakeup sweeps every shard in turn, skipping a
+    /// shard entirely, without acquiring its write lock, if none of its
+    /// entries carry an expiration. Entries it reclaims are reported to a
+    /// [`KeyValueStore::with_eviction_listener`] callback, with
+    /// [`RemovalCause::Expired`], after that shard's write lock has been
+    /// released.
+    pub fn with_eviction_interval(self, interval: Duration) -> KeyValueStore<K, V> {
+        let weak: Weak<Vec<RwLock<Inner<K, V>>>> = Arc::downgrade(&self.shards);
+        let listener = self.listener.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let shards = match weak.upgrade() {
+                Some(shards) => shards,
+                None => return,
+            };
+            for shard in shards.iter() {
+                // Peek under a read lock first so a shard with nothing
+                // expiring never pays for a write lock: real readers and
+                // writers on that shard then never contend with the
+                // janitor at all.
+                match shard.read() {
+                    Ok(inner) if inner.expiring == 0 => continue,
+                    Ok(_) => {}
+                    // The holder of the poisoned lock already surfaced
+                    // the error through the normal API; there's nothing
+                    // useful left for the janitor to do with this shard.
+                    Err(_) => continue,
+                }
+                let removed: Vec<(K, V)> = {
+                    let mut inner = match shard.write() {
+                        Ok(inner) => inner,
+                        Err(_) => continue,
+                    };
+                    if inner.expiring == 0 {
+                        continue;
+                    }
+                    let now = SystemTime::now();
+                    let expired: Vec<K> = inner
+                        .map
+                        .iter()
+                        .filter(|(_, entry)| matches!(entry.expiration, Some(exp) if exp < now))
+                        .map(|(key, _)| key.clone())
+                        .collect();
+                    let mut removed = Vec::with_capacity(expired.len());
+                    for key in expired {
+                        if let Some(entry) = inner.take(&key) {
+                            removed.push((key, entry.value));
+                        }
+                    }
+                    removed
+                };
+                if removed.is_empty() {
+                    continue;
+                }
+                if let Ok(Some(listener)) = listener.read().map(|guard| guard.clone()) {
+                    for (key, value) in removed {
+                        listener(&key, value, RemovalCause::Expired);
+                    }
+                }
+            }
+        });
+        self
+    }
+}
+
+/// A single operation staged against a [`Transaction`].
+enum StagedOp<V> {
+    Insert(V, Option<Duration>),
+    Remove,
+}
+
+/// A buffered sequence of `insert`/`remove` operations that apply
+/// atomically, inspired by fxfs's `Transaction`. Obtained from
+/// [`KeyValueStore::transaction`]; nothing touches the store until
+/// [`Transaction::commit`] is called, and dropping the guard without
+/// committing simply discards every staged operation.
+///
+/// While staged, [`Transaction::get`] layers this transaction's own
+/// pending writes over the store's committed state (read-your-writes),
+/// so a caller can read back a value it just staged without committing
+/// first.
+pub struct Transaction<'a, K, V> {
+    store: &'a mut KeyValueStore<K, V>,
+    staged: Vec<(K, StagedOp<V>)>,
+}
+
+impl<'a, K, V> Transaction<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Stage an insert of `key`, to be applied when [`Transaction::commit`]
+    /// is called. Staging the same key twice keeps only the later write.
+    pub fn insert(&mut self, key: K, value: V, expiration: Option<Duration>) -> &mut Self {
+        self.staged.push((key, StagedOp::Insert(value, expiration)));
+        self
+    }
+
+    /// Stage a removal of `key`, to be applied when [`Transaction::commit`]
+    /// is called.
+    pub fn remove(&mut self, key: K) -> &mut Self {
+        self.staged.push((key, StagedOp::Remove));
+        self
+    }
+
+    /// Read `key`, seeing this transaction's own staged writes (if any)
+    /// before falling back to the store's committed state.
+    pub fn get(&mut self, key: &K) -> KeyValueStoreResult<V> {
+        for (staged_key, op) in self.staged.iter().rev() {
+            if staged_key == key {
+                return Ok(match op {
+                    StagedOp::Insert(value, _) => Some(value.clone()),
+                    StagedOp::Remove => None,
+                });
+            }
+        }
+        self.store.get(key)
+    }
+
+    /// Apply every staged operation atomically and return the prior value
+    /// for each staged key, in staging order. Every shard touched by this
+    /// transaction is locked up front, in ascending shard order (so
+    /// concurrent transactions with overlapping shard sets can't
+    /// deadlock on each other), and held for the whole batch, so no other
+    /// caller can observe the store mid-transaction.
+    pub fn commit(self) -> Result<Vec<(K, Option<V>)>, KeyValueStoreError> {
+        let Transaction { store, staged } = self;
+        if staged.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut shard_indices: Vec<usize> = staged
+            .iter()
+            .map(|(key, _)| store.shard_index(hash_key(key)))
+            .collect();
+        shard_indices.sort_unstable();
+        shard_indices.dedup();
+
+        let mut guards: Vec<_> = shard_indices
+            .iter()
+            .map(|&index| store.write_shard_at(index))
+            .collect::<Result<_, _>>()?;
+
+        let mut prior = Vec::with_capacity(staged.len());
+        let mut removed = Vec::new();
+
+        for (key, op) in staged {
+            let key_hash = hash_key(&key);
+            let shard_index = store.shard_index(key_hash);
+            let guard_index = shard_indices.binary_search(&shard_index).unwrap();
+            let inner = &mut guards[guard_index];
+
+            match op {
+                StagedOp::Insert(value, ttl) => {
+                    if let Some(sketch) = &store.sketch {
+                        sketch
+                            .write()
+                            .map_err(|_| KeyValueStoreError::PoisonedWriteLock)?
+                            .increment(key_hash);
+                    }
+                    let expiration = ttl.map(|duration| SystemTime::now() + duration);
+                    let (previous, mut displaced) =
+                        store.apply_insert_locked(inner, key_hash, key.clone(), value, expiration)?;
+                    removed.append(&mut displaced);
+                    prior.push((key, previous));
+                }
+                StagedOp::Remove => {
+                    let previous = inner.take(&key).map(|entry| entry.value);
+                    if let Some(value) = &previous {
+                        removed.push((key.clone(), value.clone(), RemovalCause::Explicit));
+                    }
+                    prior.push((key, previous));
+                }
+            }
+        }
+
+        drop(guards);
+        store.dispatch(removed)?;
+        Ok(prior)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Mutex;
     use std::thread;
 
     #[test]
@@ -168,7 +1175,7 @@ mod tests {
         let (key, value) = (1, 1);
 
         let cloned = (kvs.clone(), key.clone(), value.clone());
-        let handle = thread::spawn(|| {
+        let handle = thread::spawn(move || {
             let (mut kvs, key, value) = cloned;
             thread::sleep(Duration::from_secs(1));
             assert_eq!(kvs.insert(key, value, None), Ok(Some(1)));
@@ -185,4 +1192,243 @@ mod tests {
         }
         handle.join().expect("should join without error");
     }
+
+    #[test]
+    fn capacity_and_len() {
+        let mut kvs = KeyValueStore::with_capacity(2);
+        assert_eq!(kvs.capacity(), Some(2));
+        assert_eq!(kvs.len(), Ok(0));
+        kvs.insert(1, 1, None).unwrap();
+        kvs.insert(2, 2, None).unwrap();
+        assert_eq!(kvs.len(), Ok(2));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut kvs = KeyValueStore::with_capacity(2);
+        kvs.insert(1, 1, None).unwrap();
+        kvs.insert(2, 2, None).unwrap();
+        // Touch `1` so `2` becomes the least-recently-used entry.
+        assert_eq!(kvs.get(&1), Ok(Some(1)));
+        // The frequency sketch only admits a new key once it's estimated
+        // to be accessed more often than the eviction victim, so the
+        // first insert of `3` is dropped and the second succeeds.
+        assert_eq!(kvs.insert(3, 3, None), Ok(None));
+        assert_eq!(kvs.len(), Ok(2));
+        assert_eq!(kvs.insert(3, 3, None), Ok(None));
+        assert_eq!(kvs.len(), Ok(2));
+        assert_eq!(kvs.get(&1), Ok(Some(1)));
+        assert_eq!(kvs.get(&3), Ok(Some(3)));
+    }
+
+    #[test]
+    fn janitor_reclaims_expired_entries_without_a_get() {
+        let mut kvs = KeyValueStore::new().with_eviction_interval(Duration::from_millis(50));
+        kvs.insert(1, 1, Some(Duration::from_millis(10))).unwrap();
+        thread::sleep(Duration::from_millis(250));
+        // Checking `len` never touches an individual key, so this can
+        // only be zero if the background janitor did the reclaiming.
+        assert_eq!(kvs.len(), Ok(0));
+    }
+
+    #[test]
+    fn eviction_listener_reports_replaced_and_explicit_removals() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = events.clone();
+        let mut kvs = KeyValueStore::new().with_eviction_listener(move |key, value, cause| {
+            events_clone.lock().unwrap().push((*key, value, cause));
+        });
+        kvs.insert(1, 1, None).unwrap();
+        kvs.insert(1, 2, None).unwrap();
+        kvs.remove(&1).unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                (1, 1, RemovalCause::Replaced),
+                (1, 2, RemovalCause::Explicit),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_or_insert_with_runs_init_once_under_contention() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Barrier;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let kvs = KeyValueStore::new();
+        let barrier = Arc::new(Barrier::new(4));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let mut kvs = kvs.clone();
+                let calls = calls.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    kvs.get_or_insert_with(1, None, || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+                        42
+                    })
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().expect("should join without error"), Ok(Some(42)));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn get_or_insert_with_recovers_after_leader_panics() {
+        use std::sync::mpsc;
+
+        let kvs = KeyValueStore::new();
+
+        let mut leader = kvs.clone();
+        let leader_handle = thread::spawn(move || {
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                leader.get_or_insert_with(1, None, || -> i32 { panic!("simulated init panic") })
+            }));
+        });
+        leader_handle.join().expect("the panic is caught inside the thread");
+
+        // A later caller for the same key must become a fresh leader,
+        // not hang forever waiting on a notification the panicked leader
+        // never sent.
+        let (tx, rx) = mpsc::channel();
+        let mut follower = kvs.clone();
+        thread::spawn(move || {
+            let _ = tx.send(follower.get_or_insert_with(1, None, || 42));
+        });
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(2))
+                .expect("should not hang"),
+            Ok(Some(42))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "with_shards can't reshard while another clone")]
+    fn with_shards_refuses_to_reshard_behind_an_existing_clone() {
+        let kvs = KeyValueStore::<i32, i32>::new();
+        let _clone = kvs.clone();
+        kvs.with_shards(4);
+    }
+
+    #[test]
+    fn shards_distribute_keys_and_preserve_semantics() {
+        let mut kvs = KeyValueStore::new().with_shards(4);
+        for key in 0..32 {
+            assert_eq!(kvs.insert(key, key * 10, None), Ok(None));
+        }
+        assert_eq!(kvs.len(), Ok(32));
+        for key in 0..32 {
+            assert_eq!(kvs.get(&key), Ok(Some(key * 10)));
+        }
+        assert_eq!(kvs.remove(&0), Ok(Some(0)));
+        assert_eq!(kvs.len(), Ok(31));
+    }
+
+    #[test]
+    fn transaction_commits_staged_operations_atomically() {
+        let mut kvs = KeyValueStore::new().with_shards(4);
+        kvs.insert(1, 1, None).unwrap();
+
+        let mut txn = kvs.transaction();
+        txn.insert(1, 10, None);
+        txn.insert(2, 20, None);
+        txn.remove(1);
+        txn.insert(1, 11, None);
+        let prior = txn.commit().unwrap();
+        assert_eq!(prior, vec![(1, Some(1)), (2, None), (1, Some(10)), (1, None)]);
+
+        assert_eq!(kvs.get(&1), Ok(Some(11)));
+        assert_eq!(kvs.get(&2), Ok(Some(20)));
+    }
+
+    #[test]
+    fn transaction_sees_its_own_staged_writes() {
+        let mut kvs = KeyValueStore::new();
+        kvs.insert(1, 1, None).unwrap();
+
+        let mut txn = kvs.transaction();
+        assert_eq!(txn.get(&1), Ok(Some(1)));
+        txn.remove(1);
+        assert_eq!(txn.get(&1), Ok(None));
+        txn.insert(2, 2, None);
+        assert_eq!(txn.get(&2), Ok(Some(2)));
+
+        // Dropped without committing, so the store is untouched.
+        drop(txn);
+        assert_eq!(kvs.get(&1), Ok(Some(1)));
+        assert_eq!(kvs.get(&2), Ok(None));
+    }
+
+    /// A value whose `clone` panics the first time it's called, used to
+    /// poison a shard's lock on demand: overwriting an existing key
+    /// clones the previous value while still holding the shard's write
+    /// lock, so forcing that clone to panic poisons the lock exactly
+    /// like a real bug in a caller's code would.
+    #[derive(Debug)]
+    struct PanicOnce(i32, Arc<std::sync::atomic::AtomicBool>);
+
+    impl PanicOnce {
+        /// A value whose first clone panics. Use this for whatever is
+        /// already in the map that an overwrite will clone on its way
+        /// out — that's the clone that poisons the lock.
+        fn armed(value: i32) -> PanicOnce {
+            PanicOnce(value, Arc::new(std::sync::atomic::AtomicBool::new(true)))
+        }
+
+        /// A value that clones normally. Use this for whatever replaces
+        /// an armed value during poisoning, so the replacement sitting
+        /// in the map afterward doesn't re-poison the shard the next
+        /// time something (like a recovered `get`) clones it back out.
+        fn inert(value: i32) -> PanicOnce {
+            PanicOnce(value, Arc::new(std::sync::atomic::AtomicBool::new(false)))
+        }
+    }
+
+    impl Clone for PanicOnce {
+        fn clone(&self) -> PanicOnce {
+            if self.1.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                panic!("simulated panic while a shard lock is held");
+            }
+            PanicOnce(self.0, self.1.clone())
+        }
+    }
+
+    fn poison_a_shard(kvs: &mut KeyValueStore<i32, PanicOnce>) {
+        kvs.insert(1, PanicOnce::armed(1), None).unwrap();
+        let mut poisoner = kvs.clone();
+        let handle = thread::spawn(move || {
+            let _ = poisoner.insert(1, PanicOnce::inert(2), None);
+        });
+        assert!(handle.join().is_err());
+    }
+
+    #[test]
+    fn clear_poison_recovers_a_poisoned_shard() {
+        let mut kvs = KeyValueStore::new();
+        poison_a_shard(&mut kvs);
+
+        assert_eq!(kvs.get(&1).unwrap_err(), KeyValueStoreError::PoisonedReadLock);
+
+        kvs.clear_poison();
+        assert!(kvs.get(&1).is_ok());
+    }
+
+    #[test]
+    fn with_poison_recovery_transparently_recovers() {
+        let mut kvs = KeyValueStore::new().with_poison_recovery();
+        poison_a_shard(&mut kvs);
+
+        // No explicit `clear_poison` needed: reads and writes recover on
+        // their own.
+        assert!(kvs.get(&1).is_ok());
+        assert!(kvs.insert(1, PanicOnce::armed(3), None).is_ok());
+    }
 }